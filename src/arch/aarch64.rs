@@ -0,0 +1,104 @@
+// AArch64 后端
+// AAPCS64 调用约定下被调用者保存的通用寄存器是 x19-x28、帧指针 x29、链接寄存器 x30
+// 以及浮点/向量寄存器 d8-d15 的低64位 (v8-v15 的低半部分)
+use std::arch::naked_asm;
+
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct ThreadContext {
+    sp: u64,
+    x19: u64,
+    x20: u64,
+    x21: u64,
+    x22: u64,
+    x23: u64,
+    x24: u64,
+    x25: u64,
+    x26: u64,
+    x27: u64,
+    x28: u64,
+    fp: u64, // x29
+    lr: u64, // x30 切换回来后从这里 ret
+    d8: u64,
+    d9: u64,
+    d10: u64,
+    d11: u64,
+    d12: u64,
+    d13: u64,
+    d14: u64,
+    d15: u64,
+}
+
+// old(x0)/new(x1) 按 AAPCS64 通过 x0/x1 传入
+// 先把当前寄存器存进 old 指向的结构体 再从 new 指向的结构体加载 最后 ret 跳到恢复出来的 lr
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
+    naked_asm!(
+        "mov x2, sp",
+        "str x2,  [x0, #0x00]",
+        "str x19, [x0, #0x08]",
+        "str x20, [x0, #0x10]",
+        "str x21, [x0, #0x18]",
+        "str x22, [x0, #0x20]",
+        "str x23, [x0, #0x28]",
+        "str x24, [x0, #0x30]",
+        "str x25, [x0, #0x38]",
+        "str x26, [x0, #0x40]",
+        "str x27, [x0, #0x48]",
+        "str x28, [x0, #0x50]",
+        "str x29, [x0, #0x58]",
+        "str x30, [x0, #0x60]",
+        "str d8,  [x0, #0x68]",
+        "str d9,  [x0, #0x70]",
+        "str d10, [x0, #0x78]",
+        "str d11, [x0, #0x80]",
+        "str d12, [x0, #0x88]",
+        "str d13, [x0, #0x90]",
+        "str d14, [x0, #0x98]",
+        "str d15, [x0, #0xa0]",
+        "ldr x2,  [x1, #0x00]",
+        "mov sp, x2",
+        "ldr x19, [x1, #0x08]",
+        "ldr x20, [x1, #0x10]",
+        "ldr x21, [x1, #0x18]",
+        "ldr x22, [x1, #0x20]",
+        "ldr x23, [x1, #0x28]",
+        "ldr x24, [x1, #0x30]",
+        "ldr x25, [x1, #0x38]",
+        "ldr x26, [x1, #0x40]",
+        "ldr x27, [x1, #0x48]",
+        "ldr x28, [x1, #0x50]",
+        "ldr x29, [x1, #0x58]",
+        "ldr x30, [x1, #0x60]",
+        "ldr d8,  [x1, #0x68]",
+        "ldr d9,  [x1, #0x70]",
+        "ldr d10, [x1, #0x78]",
+        "ldr d11, [x1, #0x80]",
+        "ldr d12, [x1, #0x88]",
+        "ldr d13, [x1, #0x90]",
+        "ldr d14, [x1, #0x98]",
+        "ldr d15, [x1, #0xa0]",
+        "ret",
+    )
+}
+
+// 把新线程的栈和上下文初始化成"即将执行 entry"的样子
+// x86-64 靠栈上两个伪造的返回地址(entry/guard)完成"执行完 f 自动跳进 guard"的把戏
+// AArch64 的返回地址走 lr 寄存器而不是栈 没法直接照搬同一招
+// 这里跟 riscv64 后端一样借一对被调用者保存寄存器(x19/x20)捎带 entry/guard 的地址
+// 再让 lr 指向 trampoline: trampoline 用 blr 调用 entry (blr 会自动把返回地址写回 lr)
+// entry 结束后自然"返回"到 trampoline 里紧跟着的下一条指令 再 blr 进 guard 完成收尾
+// sp 必须 16 字节对齐 栈顶本身已经是按 DEFAULT_STACK_SIZE 分配 天然对齐
+pub unsafe fn init_thread_stack(stack: *mut u8, size: usize, ctx: &mut ThreadContext, entry: u64, guard: u64) {
+    ctx.sp = (stack.offset(size as isize) as u64) & !0xf;
+    ctx.lr = trampoline as u64;
+    ctx.x19 = entry;
+    ctx.x20 = guard;
+}
+
+// 从 x19/x20 里取出 switch 时放进来的 entry/guard 地址 依次 blr 过去
+// entry 通过 blr 调用 返回时会回到这里紧接着再 blr 进 guard (标记线程 Available 并 yield)
+#[unsafe(naked)]
+unsafe extern "C" fn trampoline() {
+    naked_asm!("blr x19", "blr x20",)
+}
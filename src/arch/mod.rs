@@ -0,0 +1,25 @@
+// arch 模块按目标 ISA 选择寄存器集合和切换汇编
+// 对外只暴露统一的契约: ThreadContext、unsafe fn switch(old, new) 以及
+// init_thread_stack 用于在 spawn 时把新线程的栈和上下文初始化成"即将执行 entry"的样子
+// 其余 runtime 代码 (Thread/Runtime/调度) 不关心具体是哪个 ISA
+//
+// 没有随这份代码附带 Cargo.toml: 各后端已经从 1.59 之前的 asm!("..." : : : :) /
+// #![feature(asm)] 迁移到稳定的 core::arch::naked_asm!/#[unsafe(naked)] 写法
+// x86_64 这条路径在本地用临时 cargo 工程验证过能编译、能跑完 main() 里的 demo
+// aarch64/riscv64/windows 这三条没有对应的交叉编译目标 偏移量和寄存器集合是对照
+// 各自 ABI 手册核对的 而不是靠汇编/运行验证的 合入前应该在真机或对应 target 上跑一遍
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::{init_thread_stack, switch, ThreadContext};
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "aarch64")]
+pub use self::aarch64::{init_thread_stack, switch, ThreadContext};
+
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+#[cfg(target_arch = "riscv64")]
+pub use self::riscv64::{init_thread_stack, switch, ThreadContext};
@@ -0,0 +1,116 @@
+// RISC-V (rv64) 后端
+// RV64 调用约定下被调用者保存的寄存器是 ra、sp、s0-s11 (以及浮点版本 fs0-fs11)
+// 这里把浮点寄存器也存上 否则在新线程里做浮点运算会污染挂起线程的浮点状态
+use std::arch::naked_asm;
+
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct ThreadContext {
+    ra: u64,
+    sp: u64,
+    s0: u64,
+    s1: u64,
+    s2: u64,
+    s3: u64,
+    s4: u64,
+    s5: u64,
+    s6: u64,
+    s7: u64,
+    s8: u64,
+    s9: u64,
+    s10: u64,
+    s11: u64,
+    fs0: u64,
+    fs1: u64,
+    fs2: u64,
+    fs3: u64,
+    fs4: u64,
+    fs5: u64,
+    fs6: u64,
+    fs7: u64,
+    fs8: u64,
+    fs9: u64,
+    fs10: u64,
+    fs11: u64,
+}
+
+// old 通过 a0 传入 new 通过 a1 传入 (RV64 调用约定)
+// 把每个寄存器用 sd (store doubleword) 存到 old 指向的结构体里固定偏移处
+// 再用 ld (load doubleword) 从 new 指向的结构体加载 最后 ret 跳到恢复出来的 ra
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
+    naked_asm!(
+        "sd ra,  0x00(a0)",
+        "sd sp,  0x08(a0)",
+        "sd s0,  0x10(a0)",
+        "sd s1,  0x18(a0)",
+        "sd s2,  0x20(a0)",
+        "sd s3,  0x28(a0)",
+        "sd s4,  0x30(a0)",
+        "sd s5,  0x38(a0)",
+        "sd s6,  0x40(a0)",
+        "sd s7,  0x48(a0)",
+        "sd s8,  0x50(a0)",
+        "sd s9,  0x58(a0)",
+        "sd s10, 0x60(a0)",
+        "sd s11, 0x68(a0)",
+        "fsd fs0,  0x70(a0)",
+        "fsd fs1,  0x78(a0)",
+        "fsd fs2,  0x80(a0)",
+        "fsd fs3,  0x88(a0)",
+        "fsd fs4,  0x90(a0)",
+        "fsd fs5,  0x98(a0)",
+        "fsd fs6,  0xa0(a0)",
+        "fsd fs7,  0xa8(a0)",
+        "fsd fs8,  0xb0(a0)",
+        "fsd fs9,  0xb8(a0)",
+        "fsd fs10, 0xc0(a0)",
+        "fsd fs11, 0xc8(a0)",
+        "ld ra,  0x00(a1)",
+        "ld sp,  0x08(a1)",
+        "ld s0,  0x10(a1)",
+        "ld s1,  0x18(a1)",
+        "ld s2,  0x20(a1)",
+        "ld s3,  0x28(a1)",
+        "ld s4,  0x30(a1)",
+        "ld s5,  0x38(a1)",
+        "ld s6,  0x40(a1)",
+        "ld s7,  0x48(a1)",
+        "ld s8,  0x50(a1)",
+        "ld s9,  0x58(a1)",
+        "ld s10, 0x60(a1)",
+        "ld s11, 0x68(a1)",
+        "fld fs0,  0x70(a1)",
+        "fld fs1,  0x78(a1)",
+        "fld fs2,  0x80(a1)",
+        "fld fs3,  0x88(a1)",
+        "fld fs4,  0x90(a1)",
+        "fld fs5,  0x98(a1)",
+        "fld fs6,  0xa0(a1)",
+        "fld fs7,  0xa8(a1)",
+        "fld fs8,  0xb0(a1)",
+        "fld fs9,  0xb8(a1)",
+        "fld fs10, 0xc0(a1)",
+        "fld fs11, 0xc8(a1)",
+        "ret",
+    )
+}
+
+// RISC-V 没有 x86 那种"在栈上伪造返回地址"的把戏 (call/ret 不走内存 走 ra 寄存器)
+// 所以让 ra 直接指向一个 trampoline: trampoline 里先 jalr 进 entry 再 jalr 进 guard
+// entry/guard 的地址借用 s0/s1 两个被调用者保存寄存器捎带过去 这两个寄存器在线程
+// 真正开始跑用户代码之前不会被用到 所以可以安全地挪作他用
+// sp 必须 16 字节对齐 按 rv64 psABI 的要求
+pub unsafe fn init_thread_stack(stack: *mut u8, size: usize, ctx: &mut ThreadContext, entry: u64, guard: u64) {
+    ctx.sp = (stack.offset(size as isize) as u64) & !0xf;
+    ctx.ra = trampoline as u64;
+    ctx.s0 = entry;
+    ctx.s1 = guard;
+}
+
+// 从 s0/s1 里取出 switch 时放进来的 entry/guard 地址 依次跳过去
+// entry 正常返回后紧接着跳进 guard 执行收尾 (标记线程 Available 并 yield)
+#[unsafe(naked)]
+unsafe extern "C" fn trampoline() {
+    naked_asm!("jalr ra, s0, 0", "jalr ra, s1, 0",)
+}
@@ -0,0 +1,167 @@
+// x86-64 后端
+// 4个64位通用寄存器：RAX、RBX、RCX、RDX
+// 4个64位指令寄存器：RSI、RDI、RBP、RSP
+// System V AMD64 ABI 下被调用者保存的 (callee-saved) 是下面 ThreadContext 里这7个: rsp、r15-r12、rbx、rbp
+use std::arch::naked_asm;
+
+#[cfg(not(target_os = "windows"))]
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct ThreadContext {
+    // r 代表 register r是一种常见的多CPU架构中的前缀，其中的寄存器进行了编号
+    rsp: u64, // 栈指针寄存器 其内存放着一个指针，该指针永远指向系统栈最上面一个栈帧的栈顶
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64, // 基址指针寄存器，其内存放着一个指针，该指针永远指向系统栈最上面一个栈帧的底部
+}
+
+// Windows x64 调用约定比 System V 多两个被调用者保存的通用寄存器 (rdi, rsi)
+// 以及整个 XMM6-XMM15 (每个128位) 都是非易失的 必须一并保存/恢复
+// 否则从这些寄存器借用的浮点/向量状态会在一次 switch 后被悄悄破坏
+#[cfg(target_os = "windows")]
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct ThreadContext {
+    rsp: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    // 每个 XMM 寄存器是128位 用 [u64; 2] 存储 避免对 u128 对齐的依赖
+    xmm6: [u64; 2],
+    xmm7: [u64; 2],
+    xmm8: [u64; 2],
+    xmm9: [u64; 2],
+    xmm10: [u64; 2],
+    xmm11: [u64; 2],
+    xmm12: [u64; 2],
+    xmm13: [u64; 2],
+    xmm14: [u64; 2],
+    xmm15: [u64; 2],
+    // NT_TIB 栈边界 切换线程时一并换入 gs:[0x08]/gs:[0x10]
+    stack_base: u64,
+    stack_limit: u64,
+}
+
+// 内联汇编  simple分支有解释
+// 读取old线程
+// 保存和恢复执行
+// 16进制
+// 0x00 0
+// 0x08 8
+// 0x10 16
+// 因为使用了兼容c内存布局 所以我们知道数据将以这种方式在内存中表示
+// rust ABI 不保证他们在内存中以相同顺序表示 但是c ABI可以保证
+// extern "C" 在非 Windows 目标上是 System V 调用约定 old/new 按约定通过 rdi/rsi 传入
+#[cfg(not(target_os = "windows"))]
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
+    naked_asm!(
+        "mov [rdi + 0x00], rsp",
+        "mov [rdi + 0x08], r15",
+        "mov [rdi + 0x10], r14",
+        "mov [rdi + 0x18], r13",
+        "mov [rdi + 0x20], r12",
+        "mov [rdi + 0x28], rbx",
+        "mov [rdi + 0x30], rbp",
+        "mov rsp, [rsi + 0x00]",
+        "mov r15, [rsi + 0x08]",
+        "mov r14, [rsi + 0x10]",
+        "mov r13, [rsi + 0x18]",
+        "mov r12, [rsi + 0x20]",
+        "mov rbx, [rsi + 0x28]",
+        "mov rbp, [rsi + 0x30]",
+        "ret",
+    )
+}
+
+// Windows x64 的被调用者保存集合比 System V 大 (rdi, rsi, xmm6-xmm15)
+// 所以这里的偏移量要按照 windows ThreadContext 的字段顺序重新算过
+// xmm 用 movups 而不是 mov/movaps 存取: xmm 字段是128位 但 ThreadContext 里每个
+// [u64; 2] 槽位只有8字节对齐 movaps 要求16字节对齐 会直接 #GP 所以这里故意用
+// 不要求对齐的 movups 换一点点速度换正确性
+// 结构体末尾额外保存/恢复 NT_TIB 的 StackBase(gs:0x08)/StackLimit(gs:0x10)
+// extern "C" 在 Windows 目标上是 win64 调用约定 old/new 按约定通过 rcx/rdx 传入
+#[cfg(target_os = "windows")]
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
+    naked_asm!(
+        "mov [rcx + 0x00], rsp",
+        "mov [rcx + 0x08], r15",
+        "mov [rcx + 0x10], r14",
+        "mov [rcx + 0x18], r13",
+        "mov [rcx + 0x20], r12",
+        "mov [rcx + 0x28], rbx",
+        "mov [rcx + 0x30], rbp",
+        "mov [rcx + 0x38], rdi",
+        "mov [rcx + 0x40], rsi",
+        "movups [rcx + 0x48], xmm6",
+        "movups [rcx + 0x58], xmm7",
+        "movups [rcx + 0x68], xmm8",
+        "movups [rcx + 0x78], xmm9",
+        "movups [rcx + 0x88], xmm10",
+        "movups [rcx + 0x98], xmm11",
+        "movups [rcx + 0xa8], xmm12",
+        "movups [rcx + 0xb8], xmm13",
+        "movups [rcx + 0xc8], xmm14",
+        "movups [rcx + 0xd8], xmm15",
+        "mov rax, gs:[0x08]",
+        "mov [rcx + 0xe8], rax",
+        "mov rax, gs:[0x10]",
+        "mov [rcx + 0xf0], rax",
+        "mov rsp, [rdx + 0x00]",
+        "mov r15, [rdx + 0x08]",
+        "mov r14, [rdx + 0x10]",
+        "mov r13, [rdx + 0x18]",
+        "mov r12, [rdx + 0x20]",
+        "mov rbx, [rdx + 0x28]",
+        "mov rbp, [rdx + 0x30]",
+        "mov rdi, [rdx + 0x38]",
+        "mov rsi, [rdx + 0x40]",
+        "movups xmm6, [rdx + 0x48]",
+        "movups xmm7, [rdx + 0x58]",
+        "movups xmm8, [rdx + 0x68]",
+        "movups xmm9, [rdx + 0x78]",
+        "movups xmm10, [rdx + 0x88]",
+        "movups xmm11, [rdx + 0x98]",
+        "movups xmm12, [rdx + 0xa8]",
+        "movups xmm13, [rdx + 0xb8]",
+        "movups xmm14, [rdx + 0xc8]",
+        "movups xmm15, [rdx + 0xd8]",
+        "mov rax, [rdx + 0xe8]",
+        "mov gs:[0x08], rax",
+        "mov rax, [rdx + 0xf0]",
+        "mov gs:[0x10], rax",
+        "ret",
+    )
+}
+
+// 把新线程的栈和上下文初始化成"即将执行 entry"的样子
+// x86-64 的调用约定通过 ret 转移控制 所以把 entry 压在栈顶 rsp 指向它
+// entry 返回时 cpu 会接着读取下一个槽位 所以紧跟着再压一个 guard 地址
+#[cfg(not(target_os = "windows"))]
+pub unsafe fn init_thread_stack(stack: *mut u8, size: usize, ctx: &mut ThreadContext, entry: u64, guard: u64) {
+    core::ptr::write(stack.offset((size - 24) as isize) as *mut u64, guard);
+    core::ptr::write(stack.offset((size - 32) as isize) as *mut u64, entry);
+    ctx.rsp = stack.offset((size - 32) as isize) as u64;
+}
+
+// Windows 下的栈布局跟 System V 一样 (guard/entry 两个返回地址)
+// 另外把 NT_TIB 约定的 StackBase/StackLimit 记到 ctx 里 随 switch 一起换入 gs 段
+#[cfg(target_os = "windows")]
+pub unsafe fn init_thread_stack(stack: *mut u8, size: usize, ctx: &mut ThreadContext, entry: u64, guard: u64) {
+    let stack_base = stack.offset(size as isize) as u64;
+    let stack_limit = stack as u64;
+    core::ptr::write(stack.offset((size - 24) as isize) as *mut u64, guard);
+    core::ptr::write(stack.offset((size - 32) as isize) as *mut u64, entry);
+    ctx.rsp = stack.offset((size - 32) as isize) as u64;
+    ctx.stack_base = stack_base;
+    ctx.stack_limit = stack_limit;
+}
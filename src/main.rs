@@ -1,18 +1,22 @@
-#![feature(asm)] // 使用asm!宏
-#![feature(naked_functions)] // 启用裸函数特性
-                             // rust在编译函数时会为每个函数添加一些开头和结尾
-                             // 将函数标记为裸函数是为了删除开头和结尾
-                             // 目的是为了避免未对其的栈 避免切换上下文时的问题
-use std::ptr;
+mod arch;
+mod stack;
+pub mod sync;
+
+use arch::ThreadContext;
+use stack::Stack;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
 
 const DEFAULT_STACK_SIZE: usize = 1024 * 1024 * 2;
-const MAX_THREADS: usize = 4;
 static mut RUNTIME: usize = 0; // 指向运行时的指针
 
 // 创建一个运行时 以调度，切换线程
 pub struct Runtime {
-    threads: Vec<Thread>, // 线程数组
-    current: usize,       // 当前线程
+    threads: Vec<Thread>,     // 线程数组 只增不减 下标就是线程的身份
+    current: usize,           // 当前线程
+    ready_queue: VecDeque<usize>, // 就绪线程下标的队列 t_yield 从队头取下一个要跑的线程
+    pending_free: Vec<Stack>, // t_return 时退役的栈 先挪到这里 等切换到别的栈上之后再真正释放
 }
 
 // State枚举 表示线程可以处于的状态
@@ -21,50 +25,53 @@ enum State {
     Available, // 线程可用 并在需要时可分配任务
     Running,   // 线程正在运行
     Ready,     // 线程准备好继续进展，执行
+    Blocked,   // 线程在等待 Mutex/Channel 之类的原语 不会被 t_yield 排进 ready_queue
 }
 
 // Thread保存线程数据 每个线程都有一个ID 所以可以将线程分离
 // 这个Thread就是我们要实现的绿色线程
 // id 线程ID
-// stack 一块连续内存（栈）
-// Vec在调用push等方法时会重新分配内存地址 这里更好的做法是使用自定义类型
+// stack 懒分配的 mmap 栈 在第一次 spawn 到这个槽位之前都是 None
+// ctx 的具体寄存器集合由 arch 模块按目标 ISA 决定
+// closure 是 spawn 时装箱的用户闭包 线程第一次被调度到时由 call_closure 取出并调用一次
 struct Thread {
     id: usize,
-    stack: Vec<u8>,
+    stack: Option<Stack>,
     ctx: ThreadContext,
     state: State,
-}
-
-// 4个64位通用寄存器：RAX、RBX、RCX、RDX
-// 4个64位指令寄存器：RSI、RDI、RBP、RSP
-#[derive(Debug, Default)]
-#[repr(C)]
-struct ThreadContext {
-    // r 代表 register r是一种常见的多CPU架构中的前缀，其中的寄存器进行了编号
-    rsp: u64, // 栈指针寄存器 其内存放着一个指针，该指针永远指向系统栈最上面一个栈帧的栈顶
-    r15: u64,
-    r14: u64,
-    r13: u64,
-    r12: u64,
-    rbx: u64,
-    rbp: u64, // 基址指针寄存器，其内存放着一个指针，该指针永远指向系统栈最上面一个栈帧的底部
+    closure: Option<Box<dyn FnOnce()>>,
 }
 
 // 新线程在available状态下启动
-// stack分配了栈内存 这不是必须的 也不是资源最佳使用方法
-// 我们应该在首次使用时分配 而不是为一个可能需要的线程分配内存
-// 但是这降低了代码的复杂性
-// 一旦分配了内存就不能移动 也不能使用数组的push() 或其他触发内存重分配的方法
-// 这里更好的做法是创建自定义类型 只暴露安全的方法
-// Vec<T> 有一个into_boxed_slice() 方法 返回一个堆分配的切片Box<[T]>
-// 如果改为它 可以避免重新分配问题
+// 栈不在这里分配 而是留到 spawn 真正要用这个线程槽位时才 mmap
+// 这样没被用到的线程槽位不占一分栈内存 而且地址一旦分配就不会再变 不存在 Vec 重分配移动栈的风险
 impl Thread {
     fn new(id: usize) -> Self {
         Thread {
             id,
-            stack: vec![0_u8; DEFAULT_STACK_SIZE],
+            stack: None,
             ctx: ThreadContext::default(),
             state: State::Available,
+            closure: None,
+        }
+    }
+}
+
+// spawn 返回给调用者的句柄 用来取回闭包执行完之后的返回值
+// result 用 Rc<RefCell<..>> 而不是 Arc<Mutex<..>> 是因为整个 runtime 是单系统线程内的协作式调度
+// 闭包和 join() 永远不会真的并发访问它
+pub struct JoinHandle<T> {
+    result: Rc<RefCell<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    // 在闭包真正产出结果之前反复 yield 把 CPU 让给其他绿色线程
+    pub fn join(self) -> T {
+        loop {
+            if let Some(value) = self.result.borrow_mut().take() {
+                return value;
+            }
+            yield_thread();
         }
     }
 }
@@ -72,18 +79,20 @@ impl Thread {
 impl Runtime {
     // 初始线程，初始化为running状态
     pub fn new() -> Self {
+        // 0号线程代表 main() 本身运行所在的系统栈 不需要我们给它 mmap 一块栈
+        // 也不需要进 ready_queue 它从一开始就是 Running
         let base_thread = Thread {
             id: 0,
-            stack: vec![0_u8; DEFAULT_STACK_SIZE],
+            stack: None,
             ctx: ThreadContext::default(),
             state: State::Running,
+            closure: None,
         };
-        let mut threads = vec![base_thread];
-        let mut available_threads: Vec<Thread> = (1..MAX_THREADS).map(|i| Thread::new(i)).collect();
-        threads.append(&mut available_threads);
         Runtime {
-            threads,
+            threads: vec![base_thread],
             current: 0,
+            ready_queue: VecDeque::new(),
+            pending_free: Vec::new(),
         }
     }
 
@@ -105,27 +114,30 @@ impl Runtime {
     fn t_return(&mut self) {
         if self.current != 0 {
             self.threads[self.current].state = State::Available;
+            // 这里还在用这个线程自己的栈执行 不能直接 munmap 掉它
+            // 先挪进 pending_free 等 t_yield 切到别的栈上之后再真正释放
+            if let Some(stack) = self.threads[self.current].stack.take() {
+                self.pending_free.push(stack);
+            }
             self.t_yield();
         }
     }
 
     // runtime的核心
+    // 不再对 threads 做线性扫描找 Ready 线程 而是直接从 ready_queue 队头取下一个可运行线程
+    // 队列为空说明没有线程可跑 直接返回 false 交给 run() 结束调度
     fn t_yield(&mut self) -> bool {
-        let mut pos = self.current;
-        // 遍历所有其他的线程 查看是否处于就绪状
-        // 如果没有 直接返回
-        while self.threads[pos].state != State::Ready {
-            pos += 1;
-            if pos == self.threads.len() {
-                pos = 0;
-            }
-            if pos == self.current {
-                return false;
-            }
-        }
+        let pos = match self.ready_queue.pop_front() {
+            Some(pos) => pos,
+            None => return false,
+        };
 
-        if self.threads[self.current].state != State::Available {
+        // 只有"正常让出 CPU"的线程(还处于 Running)才转 Ready 重新排到队尾
+        // t_return 已经把完成的线程标成 Available block_current_thread 已经标成 Blocked
+        // 这两种情况都不应该再被放回 ready_queue
+        if self.threads[self.current].state == State::Running {
             self.threads[self.current].state = State::Ready;
+            self.ready_queue.push_back(self.current);
         }
 
         self.threads[pos].state = State::Running;
@@ -133,32 +145,78 @@ impl Runtime {
         self.current = pos;
         unsafe {
             // 调用 switch 来保存当前上下文（旧上下文）并将新上下文加载到 CPU 中
-            switch(&mut self.threads[old_pos].ctx, &self.threads[pos].ctx);
+            arch::switch(&mut self.threads[old_pos].ctx, &self.threads[pos].ctx);
         }
-        // 防止 Windows 的编译器优化我们的代码
-        self.threads.len() > 0
+        // 切换完成 现在运行在 pos 的栈上了 之前标记为退役的栈可以安全释放
+        self.pending_free.clear();
+        // 真正的终止条件是上面 ready_queue 为空那一支 能跑到这里就说明确实切换了一个线程
+        true
     }
 
-    pub fn spawn(&mut self, f: fn()) {
-        // 找到可用线程
-        let available = self
-            .threads
-            .iter_mut()
-            .find(|t| t.state == State::Available)
-            .expect("no available thread.");
-        // 获取该栈长度
-        let size = available.stack.len();
-        // 获取指向字节数组的可变指针
-        let s_ptr = available.stack.as_mut_ptr();
-        // 设置基指针为f 并16字节对齐
-        // 压入guard函数 不是16字节对齐 但f返回时cpu将读取下个地址作为f的返回值
-        // 设置rsp值 指向函数地址的栈指针
+    // f 不再要求是裸 fn() 而是可以捕获环境的闭包 装箱后存进 Thread.closure
+    // 新线程第一次被调度到时不再直接跳进用户代码 而是跳进 call_closure
+    // 由它负责从当前线程身上取出闭包并调用 这样 arch 层完全不用关心闭包是什么
+    //
+    // 池子不再有 MAX_THREADS 上限: threads 里找不到退役的 Available 槽位就在尾部新开一个
+    // 不够用就一直长 不会再因为"没有空闲线程"而 panic; 同时优先回收 t_return 留下的
+    // Available 槽位 避免每个 spawn 都在 threads 里留一具永久的尸体
+    //
+    // 不要求 F/T: Send: 所有线程都在同一个系统线程里协作式调度 从不跨系统线程执行
+    // 闭包允许捕获 Rc<RefCell<..>> 这类非 Send 的共享状态(JoinHandle/Mutex/Channel 都是这么做的)
+    // 加上 Send 反而会在 main() 里捕获 Channel 的闭包上过度报错
+    pub fn spawn<F, T>(&mut self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + 'static,
+        T: 'static,
+    {
+        // 0 号线程是 main() 本身所在的系统栈 永远处于 Running/Ready 不会变成 Available
+        // 所以这里找到的复用槽位一定来自某个真正跑完的 spawn 线程
+        let reused = self.threads.iter().position(|t| t.state == State::Available);
+        let id = reused.unwrap_or(self.threads.len());
+        let mut thread = Thread::new(id);
+
+        let result = Rc::new(RefCell::new(None));
+        let handle = JoinHandle {
+            result: result.clone(),
+        };
+        thread.closure = Some(Box::new(move || {
+            *result.borrow_mut() = Some(f());
+        }));
+
+        thread.stack = Some(Stack::allocate(DEFAULT_STACK_SIZE).expect("failed to allocate thread stack"));
+        let stack = thread.stack.as_ref().unwrap();
+        let size = stack.len();
+        let s_ptr = stack.usable_ptr();
         unsafe {
-            ptr::write(s_ptr.offset((size - 24) as isize) as *mut u64, guard as u64);
-            ptr::write(s_ptr.offset((size - 32) as isize) as *mut u64, f as u64);
-            available.ctx.rsp = s_ptr.offset((size - 32) as isize) as u64;
+            arch::init_thread_stack(
+                s_ptr,
+                size,
+                &mut thread.ctx,
+                call_closure as *const () as u64,
+                guard as *const () as u64,
+            );
+        }
+        thread.state = State::Ready;
+
+        match reused {
+            Some(pos) => self.threads[pos] = thread,
+            None => self.threads.push(thread),
+        }
+        self.ready_queue.push_back(id);
+        handle
+    }
+}
+
+// 入口 trampoline: 所有新线程第一次运行时都跳到这里 而不是直接跳到用户闭包
+// 从 RUNTIME 里拿到当前线程 把它身上装箱的闭包取出来调用一次
+// 取出后 closure 字段归位为 None 保证闭包只被调用一次
+extern "C" fn call_closure() {
+    unsafe {
+        let rt = &mut *(RUNTIME as *mut Runtime);
+        let current = rt.current;
+        if let Some(closure) = rt.threads[current].closure.take() {
+            closure();
         }
-        available.state = State::Ready;
     }
 }
 
@@ -182,63 +240,55 @@ pub fn yield_thread() {
     }
 }
 
-// 内联汇编  simple分支有解释
-// 读取old线程
-#[naked] // 裸函数
-#[inline(never)] // 阻止编译器内敛此函数 否则release模式下会运行失败
-unsafe fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
-    // 保存和恢复执行
-    // 16进制
-    // 0x00 0
-    // 0x08 8
-    // 0x10 16
-    // 因为使用了兼容c内存布局 所以我们知道数据将以这种方式在内存中表示
-    // rust ABI 不保证他们在内存中以相同顺序表示 但是c ABI可以保证
-    asm!("
-        mov %rsp, 0x00($0)
-        mov %r15, 0x08($0)
-        mov %r14, 0x10($0)
-        mov %r13, 0x18($0)
-        mov %r12, 0x20($0)
-        mov %rbx, 0x28($0)
-        mov %rbp, 0x30($0)
-
-        mov 0x00($1), %rsp
-        mov 0x08($1), %r15
-        mov 0x10($1), %r14
-        mov 0x18($1), %r13
-        mov 0x20($1), %r12
-        mov 0x28($1), %rbx
-        mov 0x30($1), %rbp
-        ret
-        "
-    :
-    :"r"(old), "r"(new)
-    :
-    : "volatile", "alignstack"
-    )
+// 供 sync 模块使用: 把当前线程标记为 Blocked 并返回它的下标
+// 调用者负责把这个下标记到自己的等待队列里 再调用 yield_thread() 真正让出 CPU
+// 假设调用该函数 runtime未初始化 或被删除 会导致未定义
+pub(crate) fn block_current_thread() -> usize {
+    unsafe {
+        let rt = &mut *(RUNTIME as *mut Runtime);
+        let id = rt.current;
+        rt.threads[id].state = State::Blocked;
+        id
+    }
+}
+
+// 供 sync 模块使用: 把一个被 Blocked 的线程唤醒 重新排进 ready_queue
+// 假设调用该函数 runtime未初始化 或被删除 会导致未定义
+pub(crate) fn wake_thread(id: usize) {
+    unsafe {
+        let rt = &mut *(RUNTIME as *mut Runtime);
+        rt.threads[id].state = State::Ready;
+        rt.ready_queue.push_back(id);
+    }
 }
 
 fn main() {
     let mut runtime = Runtime::new();
     runtime.init();
-    runtime.spawn(|| {
-        println!("THREAD 1 STARTING");
-        let id = 1;
+
+    // 线程1生产 线程2消费 channel.recv() 在队列空的时候会真正阻塞(而不是忙等)
+    // 直到线程1 send() 把它唤醒
+    let channel = sync::Channel::new();
+    let producer_channel = channel.clone();
+
+    let greeting = String::from("hello from main");
+    runtime.spawn(move || {
+        println!("THREAD 1 STARTING ({})", greeting);
         for i in 0..10 {
-            println!("thread: {} counter: {}", id, i);
+            producer_channel.send(i);
             yield_thread();
         }
         println!("THREAD 1 FINISHED");
+        1
     });
-    runtime.spawn(|| {
+    runtime.spawn(move || {
         println!("THREAD 2 STARTING");
-        let id = 2;
-        for i in 0..15 {
-            println!("thread: {} counter: {}", id, i);
-            yield_thread();
+        for _ in 0..10 {
+            let value = channel.recv();
+            println!("thread: 2 received {}", value);
         }
         println!("THREAD 2 FINISHED");
+        2
     });
     runtime.run();
 }
@@ -0,0 +1,133 @@
+// 非重定位的线程栈
+// 之前用 Vec<u8> 做栈有两个问题:
+//   1. Runtime::new 会一口气为 MAX_THREADS 个线程都分配 DEFAULT_STACK_SIZE 字节 不管用不用得上
+//   2. Vec 的内存随时可能因为别的代码误调用 push/reserve 之类的方法被重新分配 一旦发生
+//      所有存在 ctx.rsp/sp 里指向这块栈的指针就全部失效 而且不会有任何编译期或运行期提示
+// 这里换成直接 mmap 出来的一段内存 生命周期内地址永远不变 并且只在 spawn 真正用到某个
+// 线程槽位时才分配 (懒分配) 低地址端再放一个 PROT_NONE 的 guard page 栈溢出时会立刻
+// 触发 SIGSEGV 而不是悄悄踩坏堆上的其他数据
+use std::io;
+
+// MAP_ANONYMOUS 的值不是 POSIX 规定的 各 unix 之间并不通用 (Linux/Android 是 0x0020
+// macOS/*BSD 是 0x1000) 这里没有引入 libc crate 去查表 所以故意只认领我们核对过的
+// Linux/Android 其余 unix 还没有对应的 sys 实现 会直接编译失败而不是悄悄 mmap 出错误的内存
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::io;
+    use std::ptr;
+
+    extern "C" {
+        fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+        fn munmap(addr: *mut u8, len: usize) -> i32;
+        fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+    }
+
+    const PROT_NONE: i32 = 0;
+    const PROT_READ: i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const MAP_PRIVATE: i32 = 0x0002;
+    const MAP_ANONYMOUS: i32 = 0x0020;
+    const MAP_FAILED: *mut u8 = !0 as *mut u8;
+
+    // mmap 整段内存 (guard page + 可用栈) 然后把低地址端的第一页 mprotect 成 PROT_NONE
+    pub unsafe fn map(total_len: usize, guard_len: usize) -> io::Result<*mut u8> {
+        let base = mmap(
+            ptr::null_mut(),
+            total_len,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if base == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        if mprotect(base, guard_len, PROT_NONE) != 0 {
+            let err = io::Error::last_os_error();
+            munmap(base, total_len);
+            return Err(err);
+        }
+        Ok(base)
+    }
+
+    pub unsafe fn unmap(base: *mut u8, total_len: usize) {
+        munmap(base, total_len);
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::io;
+    use std::ptr;
+
+    extern "system" {
+        fn VirtualAlloc(addr: *mut u8, size: usize, alloc_type: u32, protect: u32) -> *mut u8;
+        fn VirtualFree(addr: *mut u8, size: usize, free_type: u32) -> i32;
+        fn VirtualProtect(addr: *mut u8, size: usize, new_protect: u32, old_protect: *mut u32) -> i32;
+    }
+
+    const MEM_COMMIT: u32 = 0x1000;
+    const MEM_RESERVE: u32 = 0x2000;
+    const MEM_RELEASE: u32 = 0x8000;
+    const PAGE_READWRITE: u32 = 0x04;
+    const PAGE_NOACCESS: u32 = 0x01;
+
+    pub unsafe fn map(total_len: usize, guard_len: usize) -> io::Result<*mut u8> {
+        let base = VirtualAlloc(ptr::null_mut(), total_len, MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE);
+        if base.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let mut old_protect: u32 = 0;
+        if VirtualProtect(base, guard_len, PAGE_NOACCESS, &mut old_protect) == 0 {
+            let err = io::Error::last_os_error();
+            VirtualFree(base, 0, MEM_RELEASE);
+            return Err(err);
+        }
+        Ok(base)
+    }
+
+    pub unsafe fn unmap(base: *mut u8, _total_len: usize) {
+        VirtualFree(base, 0, MEM_RELEASE);
+    }
+}
+
+const PAGE_SIZE: usize = 4096;
+
+// base/total_len 包含 guard page usable_len 是去掉 guard page 之后真正可以使用的字节数
+pub struct Stack {
+    base: *mut u8,
+    total_len: usize,
+    usable_len: usize,
+}
+
+impl Stack {
+    // size 是调用方想要的可用栈大小 实际 mmap 的区域会再往前多出一个 guard page
+    pub fn allocate(size: usize) -> io::Result<Self> {
+        let total_len = size + PAGE_SIZE;
+        let base = unsafe { sys::map(total_len, PAGE_SIZE)? };
+        Ok(Stack {
+            base,
+            total_len,
+            usable_len: size,
+        })
+    }
+
+    // 可用栈区域的起始地址 (跳过 guard page) switch/init_thread_stack 只应该碰这一段
+    pub fn usable_ptr(&self) -> *mut u8 {
+        unsafe { self.base.add(self.total_len - self.usable_len) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.usable_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.usable_len == 0
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe { sys::unmap(self.base, self.total_len) };
+    }
+}
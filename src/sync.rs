@@ -0,0 +1,136 @@
+// 协作式同步原语: Mutex<T> 和 MPSC Channel<T>
+// 在此之前唯一能用来协调多个绿色线程的工具是 yield_thread() 没有办法表达
+// "等到某个条件成立再继续" 只能自己手写忙等
+// 这里的 Mutex/Channel 都建立在调度器的 Blocked 状态之上: 拿不到锁/收不到消息时
+// 把自己标记成 Blocked 挂进对应原语的等待队列 然后让出 CPU 调度器不会再把 Blocked
+// 线程排进 ready_queue 直到 unlock()/send() 把它的下标唤醒推回队列
+//
+// 整个 runtime 运行在单个系统线程里 协程之间不会有真正的数据竞争 所以这里用
+// UnsafeCell/Rc<RefCell<..>> 而不是 Mutex/Arc 那一套 线程安全原语 跟 JoinHandle 的做法一致
+use std::cell::{RefCell, UnsafeCell};
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+// Mutex<T>: lock() 拿不到锁时阻塞当前线程 直到持有者 unlock()
+pub struct Mutex<T> {
+    locked: UnsafeCell<bool>,
+    waiters: UnsafeCell<VecDeque<usize>>,
+    value: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self {
+        Mutex {
+            locked: UnsafeCell::new(false),
+            waiters: UnsafeCell::new(VecDeque::new()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    // 锁空闲就直接拿走 否则把自己标记 Blocked 挂进等待队列再 yield
+    // 被 unlock() 唤醒之后回到循环重新尝试一次 (可能还有别的线程抢在前面)
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            unsafe {
+                if !*self.locked.get() {
+                    *self.locked.get() = true;
+                    return MutexGuard { mutex: self };
+                }
+                let id = crate::block_current_thread();
+                (*self.waiters.get()).push_back(id);
+            }
+            crate::yield_thread();
+        }
+    }
+
+    fn unlock(&self) {
+        unsafe {
+            *self.locked.get() = false;
+            if let Some(id) = (*self.waiters.get()).pop_front() {
+                crate::wake_thread(id);
+            }
+        }
+    }
+}
+
+// lock() 返回的守卫 Drop 时自动 unlock 并唤醒下一个等待者
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+struct ChannelInner<T> {
+    queue: VecDeque<T>,
+    // 等待接收的线程下标 recv() 发现队列空时把自己挂在这里
+    waiters: VecDeque<usize>,
+}
+
+// MPSC channel: 可以 clone 出多个发送端 但通常只有一个接收端在 recv()
+pub struct Channel<T> {
+    inner: Rc<RefCell<ChannelInner<T>>>,
+}
+
+impl<T> Channel<T> {
+    pub fn new() -> Self {
+        Channel {
+            inner: Rc::new(RefCell::new(ChannelInner {
+                queue: VecDeque::new(),
+                waiters: VecDeque::new(),
+            })),
+        }
+    }
+
+    // 塞进队列 如果有线程正因为队列空而 Blocked 就唤醒队头那个
+    pub fn send(&self, value: T) {
+        let mut inner = self.inner.borrow_mut();
+        inner.queue.push_back(value);
+        let woken = inner.waiters.pop_front();
+        drop(inner);
+        if let Some(id) = woken {
+            crate::wake_thread(id);
+        }
+    }
+
+    // 队列非空直接拿走 否则把自己标记 Blocked 挂进 waiters 再 yield
+    // 被 send() 唤醒后回到循环重新检查一次
+    pub fn recv(&self) -> T {
+        loop {
+            {
+                let mut inner = self.inner.borrow_mut();
+                if let Some(value) = inner.queue.pop_front() {
+                    return value;
+                }
+                let id = crate::block_current_thread();
+                inner.waiters.push_back(id);
+            }
+            crate::yield_thread();
+        }
+    }
+}
+
+impl<T> Clone for Channel<T> {
+    fn clone(&self) -> Self {
+        Channel {
+            inner: self.inner.clone(),
+        }
+    }
+}